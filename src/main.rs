@@ -1,5 +1,3 @@
-#![feature(option_result_contains)]
-
 use bevy::{
     prelude::*,
     render::{camera::Camera, pass::ClearColor},
@@ -8,19 +6,50 @@ use bevy::{
 use bevy_contrib_bobox::{BodyHandleToEntity, RapierUtilsPlugin};
 use bevy_prototype_lyon::prelude::*;
 use bevy_rapier2d::{
-    na::{Isometry2, UnitComplex, Vector2},
+    na::{Isometry2, Point2, UnitComplex, Vector2},
     physics::{EventQueue, RapierConfiguration, RapierPhysicsPlugin, RigidBodyHandleComponent},
     render::RapierRenderPlugin,
 };
 use rapier2d::{
     dynamics::{RigidBodyBuilder, RigidBodySet},
-    geometry::ColliderBuilder,
-    ncollide::{narrow_phase::ContactEvent, query::Proximity},
+    geometry::{ColliderBuilder, ColliderSet, InteractionGroups},
+    ncollide::{
+        narrow_phase::ContactEvent,
+        query::{Proximity, Ray},
+    },
+    pipeline::QueryPipeline,
 };
 use std::f32::consts::{FRAC_PI_2, PI};
 
 static PLANET_RADIUS: f32 = 200.0;
 static ATMOSPHERE_RADIUS: f32 = PLANET_RADIUS * 2.0;
+static GRAVITATIONAL_CONSTANT: f32 = 2_000.0;
+static PLANET_MASS: f32 = 4_000_000.0;
+// How fast a grounded enemy sweeps around a planet chasing the player, and how
+// hard it thrusts toward the player when they're captured by a different well.
+static ENEMY_TURN_RATE: f32 = 1.0;
+static ENEMY_SEEK_IMPULSE: f32 = 1500.0;
+static ENEMY_SURFACE_OFFSET: f32 = 20.0;
+// How much of the power budget a unit of jetpack impulse burns.
+static POWER_COST_PER_IMPULSE: f32 = 0.001;
+static BLAST_RADIUS: f32 = 120.0;
+static BLAST_IMPULSE: f32 = 60_000.0;
+// Fixed simulation step. Simulation systems advance by this constant instead of
+// `time.delta_seconds`, so replaying the same inputs takes the same steps. They
+// still run once per rendered frame, so simulated time matches real time only at
+// 60 frames per second.
+static FIXED_TIMESTEP: f32 = 1.0 / 60.0;
+
+const INPUT_LEFT: u8 = 1 << 0;
+const INPUT_RIGHT: u8 = 1 << 1;
+const INPUT_UP: u8 = 1 << 2;
+const INPUT_DOWN: u8 = 1 << 3;
+const INPUT_JUMP: u8 = 1 << 4;
+const INPUT_FIRE: u8 = 1 << 5;
+
+// Aim direction is quantized to this many steps around the circle so both peers
+// agree on it to the bit; 16 bits is finer than any pixel difference can show.
+const AIM_STEPS: f32 = u16::MAX as f32;
 
 #[derive(Debug, Default)]
 struct Planet;
@@ -31,6 +60,73 @@ struct Atmosphere;
 #[derive(Debug, Default)]
 struct Player {
     is_grounded: bool,
+    // Which slot in `Session::inputs` feeds this player. Handle 0 is the local
+    // peer, filled by `sample_inputs`; nothing fills the other slots, so
+    // `Session::get` returns an empty input for them.
+    handle: usize,
+}
+
+// Jetpack energy. Drains on airborne thrust and the grounded launch, refills
+// while the player stands on a planet. `current` holds the level for a HUD to read.
+#[derive(Debug)]
+struct Power {
+    current: f32,
+    max: f32,
+    recharge_rate: f32,
+}
+
+impl Default for Power {
+    fn default() -> Self {
+        Power {
+            current: 100.0,
+            max: 100.0,
+            recharge_rate: 50.0,
+        }
+    }
+}
+
+// Plain-old-data intent for one player on one simulation frame. Kept `Copy` and
+// free of engine handles; no code here saves, restores or sends it to another
+// peer.
+#[derive(Debug, Default, Clone, Copy)]
+struct PlayerInput {
+    buttons: u8,
+    aim: u16,
+}
+
+impl PlayerInput {
+    fn pressed(&self, flag: u8) -> bool {
+        self.buttons & flag != 0
+    }
+
+    fn with_aim(mut self, angle: f32) -> Self {
+        let turns = angle.rem_euclid(2.0 * PI) / (2.0 * PI);
+        self.aim = (turns * AIM_STEPS).round() as u16;
+        self
+    }
+
+    fn aim_radians(&self) -> f32 {
+        self.aim as f32 / AIM_STEPS * 2.0 * PI
+    }
+}
+
+// Per-handle inputs for the frame currently being simulated. A networked build
+// fills this from the GGRS session (local input plus predicted/confirmed remote
+// input); the local sampler below writes handle 0 from the real devices.
+#[derive(Debug, Default)]
+struct Session {
+    inputs: Vec<PlayerInput>,
+}
+
+impl Session {
+    fn get(&self, handle: usize) -> PlayerInput {
+        self.inputs.get(handle).copied().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Enemy {
+    is_grounded: bool,
 }
 
 #[derive(Debug, Default)]
@@ -38,8 +134,68 @@ struct Bullet {
     is_exploding: bool,
 }
 
+// Where a bullet sat at the end of the previous frame, so we can sweep a ray over
+// the segment it travelled this frame and catch tunnelling through thin colliders.
+#[derive(Debug, Default)]
+struct PreviousPosition(Vector2<f32>);
+
+// Short-lived expanding circle left behind by a detonating bullet.
 #[derive(Debug)]
-struct Attractable(Option<Entity>);
+struct Explosion {
+    timer: Timer,
+}
+
+#[derive(Debug, Default)]
+struct Attractable(Vec<Entity>);
+
+// Marks the body the spatial mixer hears the world from (the player).
+#[derive(Debug, Default)]
+struct AudioListener;
+
+// The one-shot samples the mixer can trigger.
+#[derive(Debug, Clone, Copy)]
+enum SoundKind {
+    Muzzle,
+    Impact,
+}
+
+// A positioned one-shot queued for this frame; `spatial_audio` resolves its pan
+// and gain relative to the listener.
+#[derive(Debug, Clone, Copy)]
+struct PositionedSound {
+    kind: SoundKind,
+    position: Vec2,
+}
+
+// Event queue for the spatial-audio backend. A full build drives OpenAL/Synthizer
+// sources from these values; here we resolve pan/gain and leave the device
+// hand-off to that backend crate.
+#[derive(Debug, Default)]
+struct SpatialAudio {
+    one_shots: Vec<PositionedSound>,
+    // Gain of the continuous gravity-capture rumble, 0.0 when free of any well.
+    rumble_gain: f32,
+}
+
+impl SpatialAudio {
+    fn play(&mut self, kind: SoundKind, position: Vec2) {
+        self.one_shots.push(PositionedSound { kind, position });
+    }
+}
+
+// Stereo pan (-1 left .. 1 right) and gain (1 at the listener, fading linearly
+// to 0 at twice an atmosphere's radius) for a source heard from `listener`.
+fn pan_and_gain(listener: Vec2, source: Vec2) -> (f32, f32) {
+    let offset = source - listener;
+    let distance = offset.length();
+    let gain = (1.0 - distance / (ATMOSPHERE_RADIUS * 2.0)).max(0.0);
+    let pan = if distance > std::f32::EPSILON {
+        (offset.x() / distance).max(-1.0).min(1.0)
+    } else {
+        0.0
+    };
+    (pan, gain)
+}
 
 #[derive(Debug, Default)]
 struct Cursor {
@@ -65,9 +221,9 @@ fn setup(mut commands: Commands, mut configuration: ResMut<RapierConfiguration>)
         });
 
     commands
-        .spawn((Player::default(),))
+        .spawn((Player::default(), Power::default(), AudioListener::default()))
         .with_bundle((
-            Attractable(None),
+            Attractable::default(),
             RigidBodyBuilder::new_dynamic().translation(0.0, 250.0),
             ColliderBuilder::cuboid(8.0, 23.0),
         ))
@@ -79,7 +235,6 @@ fn spawn_planets(
     mut materials: ResMut<Assets<ColorMaterial>>,
     mut meshes: ResMut<Assets<Mesh>>,
 ) {
-    // TODO change to a non-zero planet, to make movement planet-independent
     for translation in vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(750.0, 500.0, 0.0)].into_iter() {
         commands
             .spawn(primitive(
@@ -106,46 +261,110 @@ fn spawn_planets(
                 RigidBodyBuilder::new_static().translation(translation.x(), translation.y()),
                 ColliderBuilder::ball(PLANET_RADIUS),
             ));
+
+        // A couple of hunters standing on each planet's surface.
+        for offset_angle in [FRAC_PI_2, PI + FRAC_PI_2].iter() {
+            let spawn = Vector2::new(offset_angle.cos(), offset_angle.sin())
+                .scale(PLANET_RADIUS + ENEMY_SURFACE_OFFSET);
+            commands.spawn((Enemy::default(),)).with_bundle((
+                Attractable::default(),
+                RigidBodyBuilder::new_dynamic()
+                    .translation(translation.x() + spawn.x, translation.y() + spawn.y),
+                ColliderBuilder::cuboid(8.0, 20.0),
+            ));
+        }
     }
 }
 
+fn atmosphere_center(
+    bodies: &ResMut<RigidBodySet>,
+    atmosphere_query: &Query<(&Atmosphere, &RigidBodyHandleComponent)>,
+    entity: Entity,
+) -> Option<Vector2<f32>> {
+    atmosphere_query
+        .get_component::<RigidBodyHandleComponent>(entity)
+        .ok()
+        .and_then(|component| bodies.get(component.handle()))
+        .map(|body| body.position.translation.vector)
+}
+
+// Inverse-square pull of a single planet on a body, capped near the surface so
+// bodies that reach the planet don't feel a singular force.
+fn gravity_contribution(planet_center: Vector2<f32>, body_position: Vector2<f32>) -> Vector2<f32> {
+    let diff = planet_center - body_position;
+    let distance_squared = diff.magnitude_squared().max(PLANET_RADIUS.powf(2.0));
+    diff.normalize()
+        .scale(GRAVITATIONAL_CONSTANT * PLANET_MASS / distance_squared)
+}
+
+// The center of the atmosphere pulling hardest on `body_position`, i.e. the well
+// the body is currently being captured by when several overlap.
 fn get_planet_center(
     bodies: &ResMut<RigidBodySet>,
     atmosphere_query: &Query<(&Atmosphere, &RigidBodyHandleComponent)>,
     attractable: &Attractable,
+    body_position: Vector2<f32>,
 ) -> Option<Vector2<f32>> {
     attractable
         .0
-        .and_then(|entity| {
-            atmosphere_query
-                .get_component::<RigidBodyHandleComponent>(entity)
-                .ok()
+        .iter()
+        .filter_map(|entity| atmosphere_center(bodies, atmosphere_query, *entity))
+        .map(|center| (center, gravity_contribution(center, body_position).magnitude()))
+        .fold(None, |dominant, (center, pull)| match dominant {
+            Some((_, best_pull)) if best_pull >= pull => dominant,
+            _ => Some((center, pull)),
         })
-        .and_then(|component| bodies.get(component.handle()))
-        .and_then(|body| Some(body.position.translation.vector))
+        .map(|(center, _)| center)
 }
 
 fn gravitate(
     mut bodies: ResMut<RigidBodySet>,
-    attractable_body_query: Query<(&Attractable, &RigidBodyHandleComponent)>,
+    mut audio: ResMut<SpatialAudio>,
+    attractable_body_query: Query<(
+        &Attractable,
+        Option<&AudioListener>,
+        &RigidBodyHandleComponent,
+    )>,
     atmosphere_query: Query<(&Atmosphere, &RigidBodyHandleComponent)>,
 ) {
-    for (attractable, body_handle) in attractable_body_query.iter() {
-        let planet_center = match get_planet_center(&bodies, &atmosphere_query, attractable) {
-            Some(vector) => vector,
-            None => continue,
-        };
+    for (attractable, listener, body_handle) in attractable_body_query.iter() {
+        if attractable.0.is_empty() {
+            // The capture rumble follows the listener: silent once it leaves
+            // every atmosphere (the `Proximity::Disjoint` case).
+            if listener.is_some() {
+                audio.rumble_gain = 0.0;
+            }
+            continue;
+        }
 
-        let mut body = bodies.get_mut(body_handle.handle()).unwrap();
-        let diff = planet_center - body.position.translation.vector;
-        let distance_squared = diff.magnitude_squared();
-        let max_pull_distance_squared = (ATMOSPHERE_RADIUS).powf(2.0);
+        let body_position = bodies
+            .get(body_handle.handle())
+            .unwrap()
+            .position
+            .translation
+            .vector;
 
-        if distance_squared > max_pull_distance_squared {
-            continue;
+        // Sum an inverse-square contribution from every atmosphere currently
+        // overlapping the body, so it can transit between wells and get captured
+        // by whichever dominates.
+        let force = attractable
+            .0
+            .iter()
+            .filter_map(|entity| atmosphere_center(&bodies, &atmosphere_query, *entity))
+            .fold(Vector2::zeros(), |sum, center| {
+                sum + gravity_contribution(center, body_position)
+            });
+
+        // While the listener sits inside an atmosphere, scale the rumble by how
+        // hard the combined wells are tugging on it.
+        if listener.is_some() {
+            audio.rumble_gain = (force.magnitude() / 200_000.0).min(1.0);
         }
-        let gravity = diff.normalize().scale(200_000.0);
-        body.apply_force(gravity);
+
+        bodies
+            .get_mut(body_handle.handle())
+            .unwrap()
+            .apply_force(force);
     }
 }
 
@@ -155,10 +374,17 @@ fn graviturn(
     atmosphere_query: Query<(&Atmosphere, &RigidBodyHandleComponent)>,
 ) {
     for (_, attractable, body_handle_component) in player_query.iter() {
-        let planet_center = match get_planet_center(&bodies, &atmosphere_query, attractable) {
-            Some(vector) => vector,
-            None => continue,
-        };
+        let body_position = bodies
+            .get(body_handle_component.handle())
+            .unwrap()
+            .position
+            .translation
+            .vector;
+        let planet_center =
+            match get_planet_center(&bodies, &atmosphere_query, attractable, body_position) {
+                Some(vector) => vector,
+                None => continue,
+            };
 
         let mut body = bodies.get_mut(body_handle_component.handle()).unwrap();
 
@@ -177,31 +403,92 @@ fn graviturn(
     }
 }
 
+// Sample the local devices into handle 0 of the session. In a networked build
+// the GGRS session owns this frame's inputs for every handle; here we translate
+// keyboard/mouse and the resolved cursor into the serializable `PlayerInput`.
+fn sample_inputs(
+    mut session: ResMut<Session>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    cursor: Res<Cursor>,
+    bodies: Res<RigidBodySet>,
+    player_query: Query<(&Player, &RigidBodyHandleComponent)>,
+) {
+    for (player, body_handle_component) in player_query.iter() {
+        if player.handle != 0 {
+            continue;
+        }
+        let mut buttons = 0u8;
+        for (key_code, flag) in [
+            (KeyCode::A, INPUT_LEFT),
+            (KeyCode::D, INPUT_RIGHT),
+            (KeyCode::W, INPUT_UP),
+            (KeyCode::S, INPUT_DOWN),
+            (KeyCode::Space, INPUT_JUMP),
+        ]
+        .iter()
+        {
+            if keyboard_input.pressed(*key_code) {
+                buttons |= *flag;
+            }
+        }
+        // Edge-triggered off the local device. A real rollback session would need
+        // inputs sampled deterministically per confirmed frame instead; this is
+        // input-routing prep, not the rollback transport itself.
+        if mouse_button_input.just_pressed(MouseButton::Left) {
+            buttons |= INPUT_FIRE;
+        }
+
+        let aim = bodies
+            .get(body_handle_component.handle())
+            .map(|body| {
+                let body_vector =
+                    Vec2::from_slice_unaligned(body.position.translation.vector.as_slice());
+                let direction = cursor.world_position - body_vector;
+                direction.y().atan2(direction.x())
+            })
+            .unwrap_or_default();
+
+        if session.inputs.len() <= player.handle {
+            session.inputs.resize(player.handle + 1, PlayerInput::default());
+        }
+        session.inputs[player.handle] = PlayerInput { buttons, aim: 0 }.with_aim(aim);
+    }
+}
+
 fn move_player(
     mut bodies: ResMut<RigidBodySet>,
-    time: Res<Time>,
-    keyboard_input: Res<Input<KeyCode>>,
-    player_query: Query<(&Player, &Attractable, &RigidBodyHandleComponent)>,
+    session: Res<Session>,
+    mut player_query: Query<(&Player, &Attractable, &RigidBodyHandleComponent, &mut Power)>,
     atmosphere_query: Query<(&Atmosphere, &RigidBodyHandleComponent)>,
 ) {
-    for (player, attractable, body_handle_component) in player_query.iter() {
-        let planet_center = match get_planet_center(&bodies, &atmosphere_query, attractable) {
-            Some(vector) => vector,
-            None => continue,
-        };
+    for (player, attractable, body_handle_component, mut power) in player_query.iter_mut() {
+        let body_position = bodies
+            .get(body_handle_component.handle())
+            .unwrap()
+            .position
+            .translation
+            .vector;
+        let planet_center =
+            match get_planet_center(&bodies, &atmosphere_query, attractable, body_position) {
+                Some(vector) => vector,
+                None => continue,
+            };
+        let input = session.get(player.handle);
+
         let mut body = bodies.get_mut(body_handle_component.handle()).unwrap();
         let diff = planet_center - body.position.translation.vector;
 
-        let directions: [(KeyCode, (f32, f32)); 4] = [
-            (KeyCode::A, (-1.0, 0.0)),
-            (KeyCode::D, (1.0, 0.0)),
-            (KeyCode::W, (0.0, 1.0)),
-            (KeyCode::S, (0.0, -1.0)),
+        let directions: [(u8, (f32, f32)); 4] = [
+            (INPUT_LEFT, (-1.0, 0.0)),
+            (INPUT_RIGHT, (1.0, 0.0)),
+            (INPUT_UP, (0.0, 1.0)),
+            (INPUT_DOWN, (0.0, -1.0)),
         ];
         let direction = directions
             .iter()
-            .fold(Vector2::zeros(), |sum, (key_code, v)| {
-                if keyboard_input.pressed(*key_code) {
+            .fold(Vector2::zeros(), |sum, (flag, v)| {
+                if input.pressed(*flag) {
                     sum + Vector2::new(v.0, v.1)
                 } else {
                     sum
@@ -214,17 +501,20 @@ fn move_player(
         let is_clockwise = direction.angle(&clockwise) < FRAC_PI_2;
         let direction_factor = if is_clockwise { 1.0 } else { -1.0 };
 
-        if player.is_grounded && keyboard_input.pressed(KeyCode::Space) {
-            body.apply_impulse(
-                (diff
+        if player.is_grounded && input.pressed(INPUT_JUMP) {
+            if power.current > 0.0 {
+                let impulse = (diff
                     + if has_direction {
                         clockwise * direction_factor
                     } else {
                         Vector2::default()
                     })
                 .normalize()
-                    * -30000.0,
-            );
+                    * -30000.0;
+                power.current =
+                    (power.current - impulse.magnitude() * POWER_COST_PER_IMPULSE).max(0.0);
+                body.apply_impulse(impulse);
+            }
             continue;
         }
 
@@ -233,22 +523,113 @@ fn move_player(
         }
 
         if !player.is_grounded {
-            body.apply_impulse((clockwise * direction_factor * 2.0 + diff.normalize()) * 1000.0);
+            if power.current > 0.0 {
+                let impulse = (clockwise * direction_factor * 2.0 + diff.normalize()) * 1000.0;
+                power.current =
+                    (power.current - impulse.magnitude() * POWER_COST_PER_IMPULSE).max(0.0);
+                body.apply_impulse(impulse);
+            }
             continue;
         }
 
         let planet_angle = diff.y.atan2(diff.x);
-        let new_planet_angle = PI + planet_angle + time.delta_seconds * 1.2 * direction_factor;
+        let new_planet_angle = PI + planet_angle + FIXED_TIMESTEP * 1.2 * direction_factor;
 
         let body_angle = body.position.rotation.angle();
         body.set_position(Isometry2::new(
-            Vector2::new(new_planet_angle.cos(), new_planet_angle.sin())
-                .scale(PLANET_RADIUS + 23.0),
+            planet_center
+                + Vector2::new(new_planet_angle.cos(), new_planet_angle.sin())
+                    .scale(PLANET_RADIUS + 23.0),
             body_angle,
         ));
     }
 }
 
+// Grounded enemies chase the player around their shared planet by stepping their
+// polar angle toward the player's; when the two are captured by different wells
+// the enemy just thrusts straight at the player and rides the gravity in.
+fn enemy_ai(
+    mut bodies: ResMut<RigidBodySet>,
+    atmosphere_query: Query<(&Atmosphere, &RigidBodyHandleComponent)>,
+    player_query: Query<(&Player, &Attractable, &RigidBodyHandleComponent)>,
+    enemy_query: Query<(&Enemy, &Attractable, &RigidBodyHandleComponent)>,
+) {
+    let (player_attractable, player_handle) = match player_query.iter().next() {
+        Some((_, attractable, body_handle)) => (attractable, body_handle),
+        None => return,
+    };
+    let player_position = match bodies.get(player_handle.handle()) {
+        Some(body) => body.position.translation.vector,
+        None => return,
+    };
+    let player_planet =
+        get_planet_center(&bodies, &atmosphere_query, player_attractable, player_position);
+
+    for (enemy, attractable, body_handle) in enemy_query.iter() {
+        let enemy_position = match bodies.get(body_handle.handle()) {
+            Some(body) => body.position.translation.vector,
+            None => continue,
+        };
+        let enemy_planet =
+            get_planet_center(&bodies, &atmosphere_query, attractable, enemy_position);
+
+        // Same planet and on the ground: walk around the surface toward the player.
+        let shared_planet = match (enemy_planet, player_planet) {
+            (Some(enemy_center), Some(player_center))
+                if (enemy_center - player_center).magnitude_squared() < 1.0 =>
+            {
+                Some(enemy_center)
+            }
+            _ => None,
+        };
+
+        if let (true, Some(center)) = (enemy.is_grounded, shared_planet) {
+            let enemy_angle = {
+                let diff = center - enemy_position;
+                PI + diff.y.atan2(diff.x)
+            };
+            let player_angle = {
+                let diff = center - player_position;
+                PI + diff.y.atan2(diff.x)
+            };
+            // Signed shortest way around the circle, capped per frame.
+            let delta = (player_angle - enemy_angle + PI).rem_euclid(2.0 * PI) - PI;
+            let step = delta.max(-ENEMY_TURN_RATE * FIXED_TIMESTEP).min(ENEMY_TURN_RATE * FIXED_TIMESTEP);
+            let new_angle = enemy_angle + step;
+
+            let mut body = bodies.get_mut(body_handle.handle()).unwrap();
+            let rotation = body.position.rotation.angle();
+            body.set_position(Isometry2::new(
+                center
+                    + Vector2::new(new_angle.cos(), new_angle.sin())
+                        .scale(PLANET_RADIUS + ENEMY_SURFACE_OFFSET),
+                rotation,
+            ));
+            continue;
+        }
+
+        // Different wells (or airborne): drift toward the player and let gravity finish.
+        let to_player = player_position - enemy_position;
+        if to_player.magnitude_squared() > 0.0 {
+            bodies
+                .get_mut(body_handle.handle())
+                .unwrap()
+                .apply_impulse(to_player.normalize() * ENEMY_SEEK_IMPULSE);
+        }
+    }
+}
+
+// Refill a grounded player's jetpack toward `max`; airborne players coast. Uses
+// the fixed step since `Power` gates thrust and is therefore simulation state.
+fn update_power(mut query: Query<(&Player, &mut Power)>) {
+    for (player, mut power) in query.iter_mut() {
+        if player.is_grounded {
+            power.current =
+                (power.current + power.recharge_rate * FIXED_TIMESTEP).min(power.max);
+        }
+    }
+}
+
 fn aim(
     mut cursor: ResMut<Cursor>,
     mut state: Local<MouseState>,
@@ -276,23 +657,27 @@ fn aim(
 fn shoot(
     mut commands: Commands,
     mut bodies: ResMut<RigidBodySet>,
-    cursor: Res<Cursor>,
-    mouse_button_input: Res<Input<MouseButton>>,
-    _player: &Player,
+    mut audio: ResMut<SpatialAudio>,
+    session: Res<Session>,
+    player: &Player,
     body_handle_component: &RigidBodyHandleComponent,
 ) {
+    let input = session.get(player.handle);
     let body = bodies.get_mut(body_handle_component.handle()).unwrap();
-    if mouse_button_input.just_pressed(MouseButton::Left) {
+    if input.pressed(INPUT_FIRE) {
         let body_vector = Vec2::from_slice_unaligned(body.position.translation.vector.as_slice());
-        let direction = (cursor.world_position - body_vector).normalize();
+        let aim = input.aim_radians();
+        let direction = Vec2::new(aim.cos(), aim.sin());
         let start_at = body_vector + direction * 30.0;
         let vel = direction * 700.0;
+        audio.play(SoundKind::Muzzle, start_at);
         let entity = commands
             .spawn((Bullet::default(),))
             .current_entity()
             .unwrap();
         commands.with_bundle((
-            Attractable(None),
+            Attractable::default(),
+            PreviousPosition(Vector2::new(start_at.x(), start_at.y())),
             RigidBodyBuilder::new_dynamic()
                 .translation(start_at.x(), start_at.y())
                 .linvel(vel.x(), vel.y()),
@@ -301,17 +686,84 @@ fn shoot(
     }
 }
 
+// Sweep each bullet's path since last frame and catch hits the discrete solver
+// would miss at 700 units/s. Runs before `physics_events` so a tunnelled bullet
+// is snapped onto the surface it should have struck.
+fn sweep_bullets(
+    mut query_pipeline: Local<QueryPipeline>,
+    mut bodies: ResMut<RigidBodySet>,
+    colliders: Res<ColliderSet>,
+    mut bullet_query: Query<(
+        Entity,
+        &mut Bullet,
+        &PreviousPosition,
+        &RigidBodyHandleComponent,
+    )>,
+) {
+    query_pipeline.update(&bodies, &colliders);
+
+    for (entity, mut bullet, previous, body_handle) in bullet_query.iter_mut() {
+        let handle = body_handle.handle();
+        let current = match bodies.get(handle) {
+            Some(body) => body.position.translation.vector,
+            None => continue,
+        };
+
+        let travel = current - previous.0;
+        let distance = travel.magnitude();
+        if distance <= std::f32::EPSILON {
+            continue;
+        }
+
+        let ray = Ray::new(Point2::from(previous.0), travel / distance);
+        let hit = query_pipeline
+            .cast_ray_and_get_normal(&colliders, &ray, distance, false, InteractionGroups::all())
+            .filter(|(collider_handle, intersection)| {
+                // Accept only a real crossing of a solid collider: ignore hits at
+                // (or inside) the muzzle, the bullet's own collider at the far end
+                // of the sweep, and the atmosphere sensors the bullet starts in.
+                intersection.toi > std::f32::EPSILON
+                    && intersection.toi < distance
+                    && colliders.get(*collider_handle).map_or(false, |collider| {
+                        !collider.is_sensor() && collider.user_data != u128::from(entity.id())
+                    })
+            });
+
+        if let Some((_, intersection)) = hit {
+            let point = previous.0 + travel / distance * intersection.toi;
+            let mut body = bodies.get_mut(handle).unwrap();
+            body.position = Isometry2::from_parts(point.into(), body.position.rotation);
+            bullet.is_exploding = true;
+        }
+    }
+}
+
+// Record where every bullet ends the frame, for next frame's sweep.
+fn track_previous_position(
+    bodies: Res<RigidBodySet>,
+    mut query: Query<(&mut PreviousPosition, &RigidBodyHandleComponent)>,
+) {
+    for (mut previous, body_handle) in query.iter_mut() {
+        if let Some(body) = bodies.get(body_handle.handle()) {
+            previous.0 = body.position.translation.vector;
+        }
+    }
+}
+
 fn physics_events(
     mut commands: Commands,
     events: Res<EventQueue>,
     body_handle_to_entity: Res<BodyHandleToEntity>,
+    bodies: Res<RigidBodySet>,
+    mut audio: ResMut<SpatialAudio>,
 
     atmosphere_query: Query<(&Atmosphere, &RigidBodyHandleComponent)>,
     mut attractable_query: Query<&mut Attractable>,
 
     planet_query: Query<&Planet>,
     mut player_query: Query<&mut Player>,
-    bullet_query: Query<&Bullet>,
+    mut enemy_query: Query<&mut Enemy>,
+    mut bullet_query: Query<(&mut Bullet, &RigidBodyHandleComponent)>,
 ) {
     while let Ok(proximity_event) = events.proximity_events.pop() {
         let entities: Vec<Entity> = [proximity_event.collider1, proximity_event.collider2]
@@ -335,13 +787,13 @@ fn physics_events(
                     };
                 match proximity_event.new_status {
                     Proximity::Intersecting => {
-                        attractable.0 = Some(atmosphere_entity.clone());
+                        if !attractable.0.contains(atmosphere_entity) {
+                            attractable.0.push(*atmosphere_entity);
+                        }
                     }
                     Proximity::WithinMargin => {}
                     Proximity::Disjoint => {
-                        if attractable.0.contains(atmosphere_entity) {
-                            attractable.0 = None;
-                        }
+                        attractable.0.retain(|entity| entity != atmosphere_entity);
                     }
                 };
             }
@@ -359,11 +811,40 @@ fn physics_events(
             .map(|entity| *entity)
             .collect();
 
-        for bullet_entity in entities
-            .iter()
-            .filter(|entity| bullet_query.get(**entity).is_ok())
-        {
-            commands.despawn(*bullet_entity);
+        // Don't despawn on contact any more: flag the bullet so `explode` can
+        // apply knockback before `despawn_exploded_bullets` removes it.
+        if is_started {
+            for entity in &entities {
+                if bullet_query.get_component::<Bullet>(*entity).is_err() {
+                    continue;
+                }
+                if let Ok(mut bullet) = bullet_query.get_component_mut::<Bullet>(*entity) {
+                    bullet.is_exploding = true;
+                }
+                // Impact report at the contact location — for a bullet-sized
+                // collider its body position this step is the contact point.
+                if let Some(body) = bullet_query
+                    .get_component::<RigidBodyHandleComponent>(*entity)
+                    .ok()
+                    .and_then(|handle| bodies.get(handle.handle()))
+                {
+                    let position =
+                        Vec2::from_slice_unaligned(body.position.translation.vector.as_slice());
+                    audio.play(SoundKind::Impact, position);
+                }
+            }
+
+            // A bullet striking an enemy kills it outright.
+            let hit_by_bullet = entities
+                .iter()
+                .any(|entity| bullet_query.get_component::<Bullet>(*entity).is_ok());
+            if hit_by_bullet {
+                for entity in &entities {
+                    if enemy_query.get_component::<Enemy>(*entity).is_ok() {
+                        commands.despawn(*entity);
+                    }
+                }
+            }
         }
 
         if entities
@@ -374,11 +855,143 @@ fn physics_events(
                 if let Ok(mut player) = player_query.get_component_mut::<Player>(entity) {
                     player.is_grounded = is_started;
                 }
+                if let Ok(mut enemy) = enemy_query.get_component_mut::<Enemy>(entity) {
+                    enemy.is_grounded = is_started;
+                }
+            }
+        }
+    }
+}
+
+// For every bullet flagged this frame, drop an expanding visual and shove nearby
+// dynamic bodies radially away with a distance falloff. Runs in HANDLE_EXPLOSION.
+fn explode(
+    mut commands: Commands,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut bodies: ResMut<RigidBodySet>,
+    bullet_query: Query<(Entity, &Bullet, &RigidBodyHandleComponent)>,
+    attractable_query: Query<(Entity, &Attractable, &RigidBodyHandleComponent)>,
+) {
+    let blasts: Vec<(Entity, Vector2<f32>)> = bullet_query
+        .iter()
+        .filter(|(_, bullet, _)| bullet.is_exploding)
+        .filter_map(|(entity, _, body_handle)| {
+            bodies
+                .get(body_handle.handle())
+                .map(|body| (entity, body.position.translation.vector))
+        })
+        .collect();
+
+    for (bullet_entity, center) in blasts {
+        commands
+            .spawn(primitive(
+                materials.add(Color::rgba(1.0, 0.6, 0.2, 0.6).into()),
+                &mut meshes,
+                ShapeType::Circle(BLAST_RADIUS),
+                TessellationMode::Fill(&FillOptions::default()),
+                Vec3::new(center.x, center.y, 0.0),
+            ))
+            .with(Explosion {
+                timer: Timer::from_seconds(0.3, false),
+            });
+
+        for (entity, _, body_handle) in attractable_query.iter() {
+            if entity == bullet_entity {
+                continue;
+            }
+            let mut body = match bodies.get_mut(body_handle.handle()) {
+                Some(body) => body,
+                None => continue,
+            };
+            if !body.is_dynamic() {
+                continue;
             }
+
+            let diff = body.position.translation.vector - center;
+            let distance = diff.magnitude();
+            if distance >= BLAST_RADIUS {
+                continue;
+            }
+            let direction = if distance > std::f32::EPSILON {
+                diff / distance
+            } else {
+                Vector2::new(0.0, 1.0)
+            };
+            let falloff = 1.0 - distance / BLAST_RADIUS;
+            body.apply_impulse(direction * BLAST_IMPULSE * falloff);
+        }
+    }
+}
+
+// Grow the explosion circle over its lifetime and despawn it once spent.
+fn animate_explosions(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut query: Query<(Entity, &mut Explosion, &mut Transform)>,
+) {
+    for (entity, mut explosion, mut transform) in query.iter_mut() {
+        explosion.timer.tick(time.delta_seconds);
+        let progress = (explosion.timer.elapsed / explosion.timer.duration).min(1.0);
+        transform.scale = Vec3::one() * (0.1 + progress * 0.9);
+        if explosion.timer.finished {
+            commands.despawn(entity);
         }
     }
 }
 
+// Reap bullets that detonated this frame, after their blast has been applied.
+fn despawn_exploded_bullets(mut commands: Commands, query: Query<(Entity, &Bullet)>) {
+    for (entity, bullet) in query.iter() {
+        if bullet.is_exploding {
+            commands.despawn(entity);
+        }
+    }
+}
+
+// Resolve every queued source's pan and gain against the listener and the current
+// camera zoom, then empty the queue. No audio backend is attached: the resolved
+// values are dropped and nothing is played.
+fn spatial_audio(
+    mut audio: ResMut<SpatialAudio>,
+    bodies: Res<RigidBodySet>,
+    listener_query: Query<(&AudioListener, &RigidBodyHandleComponent)>,
+    camera_query: Query<(&Camera, &Transform)>,
+) {
+    let listener = listener_query
+        .iter()
+        .next()
+        .and_then(|(_, body_handle)| bodies.get(body_handle.handle()))
+        .map(|body| Vec2::from_slice_unaligned(body.position.translation.vector.as_slice()));
+
+    let listener = match listener {
+        Some(listener) => listener,
+        None => {
+            audio.one_shots.clear();
+            return;
+        }
+    };
+
+    // The camera zoom resolved for `aim` also sets how distant a source feels.
+    let scale = camera_query
+        .iter()
+        .next()
+        .map(|(_, transform)| transform.scale.x())
+        .unwrap_or(1.0)
+        .max(std::f32::EPSILON);
+
+    for sound in audio.one_shots.drain(..).collect::<Vec<_>>() {
+        let (pan, gain) = pan_and_gain(listener, sound.position);
+        // Hand (kind, pan, attenuated gain) to the spatial backend here.
+        let _ = (sound.kind, pan, gain / scale);
+    }
+
+    // The gravity-capture rumble emanates from the listener, so it has no pan;
+    // only its gain (set in `gravitate` from pull strength) varies, scaled by the
+    // zoom like the one-shots.
+    let _ = audio.rumble_gain / scale;
+}
+
 fn main() {
     App::build()
         .add_resource(WindowDescriptor {
@@ -394,6 +1007,8 @@ fn main() {
         )))
         .add_resource(Msaa::default())
         .init_resource::<Cursor>()
+        .init_resource::<Session>()
+        .init_resource::<SpatialAudio>()
         //
         .add_stage_after(stage::POST_UPDATE, "HANDLE_CONTACT")
         .add_stage_after("HANDLE_CONTACT", "HANDLE_EXPLOSION")
@@ -409,11 +1024,25 @@ fn main() {
         .add_startup_system(spawn_planets.system())
         //
         .add_system(bevy::input::system::exit_on_esc_system.system())
+        // `move_player` and `shoot` read their input only through
+        // `Session`/`PlayerInput` rather than the raw devices (`gravitate` and
+        // `graviturn` read no input), so a
+        // later rollback session can drive them from saved/replayed inputs. That
+        // session (and the snapshotting it needs) is out of scope here.
+        .add_system_to_stage(stage::UPDATE, aim.system())
+        .add_system_to_stage(stage::UPDATE, sample_inputs.system())
         .add_system_to_stage(stage::UPDATE, gravitate.system())
         .add_system_to_stage(stage::UPDATE, graviturn.system())
         .add_system_to_stage(stage::UPDATE, move_player.system())
-        .add_system_to_stage(stage::UPDATE, aim.system())
+        .add_system_to_stage(stage::UPDATE, enemy_ai.system())
+        .add_system_to_stage(stage::UPDATE, update_power.system())
         .add_system_to_stage(stage::UPDATE, shoot.system())
+        .add_system_to_stage(stage::UPDATE, animate_explosions.system())
+        .add_system_to_stage(stage::POST_UPDATE, sweep_bullets.system())
         .add_system_to_stage(stage::POST_UPDATE, physics_events.system())
+        .add_system_to_stage("HANDLE_EXPLOSION", explode.system())
+        .add_system_to_stage("CLEANUP", despawn_exploded_bullets.system())
+        .add_system_to_stage("CLEANUP", track_previous_position.system())
+        .add_system_to_stage("CLEANUP", spatial_audio.system())
         .run();
 }